@@ -1,16 +1,56 @@
+use std::borrow::Borrow;
 use std::cmp::Eq;
+use std::collections::hash_map;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::error;
+use std::fmt;
 use std::hash::Hash;
+use std::rc::Rc;
 
 type ChainMapType<K, V> = HashMap<K, V>;
 
+/// Errors returned by fallible `ChainMap` operations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// `insert_at` was given a layer index past the end of the chain.
+    IndexOutOfRange(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::IndexOutOfRange(idx) => write!(f, "layer index {} is out of range", idx),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+/// A single layer of a `ChainMap`, reference-counted so that slicing a chain
+/// (see [`ChainMap::parents`]/[`ChainMap::children`]) or cloning it shares the
+/// underlying maps instead of deep-copying them.
+type Layer<K, V> = Rc<ChainMapType<K, V>>;
+
 pub struct ChainMap<K, V> {
-    maps: Vec<ChainMapType<K, V>>,
+    maps: Vec<Layer<K, V>>,
+}
+
+impl<K, V> Clone for ChainMap<K, V> {
+    /// Clones the chain of layers; O(number of layers), not a deep copy
+    fn clone(&self) -> Self {
+        ChainMap {
+            maps: self.maps.clone(),
+        }
+    }
 }
 
 impl<K: Hash + Eq, V> ChainMap<K, V> {
     /// Returns the first element found in the maps chain or None
-    pub fn get(&self, k: &K) -> Option<&V> {
+    pub fn get<Q: ?Sized + Hash + Eq>(&self, k: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+    {
         // Check whether an element is found in any one of the maps
         for m in &self.maps {
             let r = m.get(k);
@@ -21,15 +61,12 @@ impl<K: Hash + Eq, V> ChainMap<K, V> {
         None
     }
 
-    /// Inserts an element to the first map of the chain if maps are not empty,
-    /// and returns the current value in the case where the key exists, or
-    /// otherwise None
-    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
-        if self.maps.len() == 0 {
-            None
-        } else {
-            self.maps[0].insert(k, v)
-        }
+    /// Returns whether the given key is present in any one of the maps
+    pub fn contains_key<Q: ?Sized + Hash + Eq>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+    {
+        self.maps.iter().any(|m| m.contains_key(k))
     }
 
     /// Returns whether the ChainMap is empty or not
@@ -42,9 +79,39 @@ impl<K: Hash + Eq, V> ChainMap<K, V> {
         true
     }
 
+    /// Returns the number of distinct visible keys across the chain, i.e.
+    /// the size `to_map()` would have without allocating it.
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// Returns an iterator over the chain's visible `(key, value)` pairs,
+    /// walking layers front-to-back and yielding each key only once: the
+    /// first, shadowing occurrence, matching `get`.
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            layers: self.maps.iter(),
+            current: None,
+            seen: HashSet::new(),
+        }
+    }
+
+    /// Returns an iterator over the chain's visible keys, each yielded once.
+    pub fn keys(&self) -> Keys<'_, K, V> {
+        Keys { inner: self.iter() }
+    }
+
+    /// Returns an iterator over the chain's visible values, one per
+    /// distinct visible key.
+    pub fn values(&self) -> Values<'_, K, V> {
+        Values { inner: self.iter() }
+    }
+
     /// Constructs a ChainMap with given vector of sub-maps
     pub fn new(maps: Vec<ChainMapType<K, V>>) -> Self {
-        ChainMap { maps }
+        ChainMap {
+            maps: maps.into_iter().map(Rc::new).collect(),
+        }
     }
 
     /// Constructs an empty ChainMap
@@ -54,23 +121,22 @@ impl<K: Hash + Eq, V> ChainMap<K, V> {
 
     /// Adds a map to the end of the maps chain
     pub fn add_map(&mut self, m: ChainMapType<K, V>) -> &mut Self {
-        self.maps.push(m);
+        self.maps.push(Rc::new(m));
         self
     }
-}
-
-impl<K: Hash + Eq + Clone, V: Clone> ChainMap<K, V> {
-    /// Returns a single consolidated map
-    pub fn to_map(&self) -> ChainMapType<K, V> {
-        let mut combined_map = ChainMapType::new();
 
-        for m in &self.maps {
-            for (k, v) in m {
-                combined_map.insert(k.clone(), v.clone());
-            }
-        }
+    /// Returns a new ChainMap with `map` prepended as a fresh writable layer
+    /// in front of this chain's existing layers
+    pub fn new_child(&self, map: ChainMapType<K, V>) -> Self {
+        let mut maps = Vec::with_capacity(self.maps.len() + 1);
+        maps.push(Rc::new(map));
+        maps.extend(self.maps.iter().cloned());
+        ChainMap { maps }
+    }
 
-        combined_map
+    /// Pushes a fresh, empty writable layer onto the front of the chain
+    pub fn push_scope(&mut self) {
+        self.maps.insert(0, Rc::new(ChainMapType::new()));
     }
 
     /// Returns an optional ChainMap with just the end of maps removed, if the
@@ -96,6 +162,241 @@ impl<K: Hash + Eq + Clone, V: Clone> ChainMap<K, V> {
     }
 }
 
+impl<K: Hash + Eq + Clone, V: Clone> ChainMap<K, V> {
+    /// Inserts an element into the front (writable) layer, creating one
+    /// first if the chain is empty, and returns the current value in the
+    /// case where the key exists, or otherwise None
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        if self.maps.is_empty() {
+            self.maps.push(Rc::new(ChainMapType::new()));
+        }
+        Rc::make_mut(&mut self.maps[0]).insert(k, v)
+    }
+
+    /// Removes `k` from the front (writable) layer only, unshadowing any
+    /// deeper binding, and returns its value
+    pub fn remove<Q: ?Sized + Hash + Eq>(&mut self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
+        if self.maps.is_empty() || !self.maps[0].contains_key(k) {
+            None
+        } else {
+            Rc::make_mut(&mut self.maps[0]).remove(k)
+        }
+    }
+
+    /// Removes `k` from every layer of the chain, unlike `remove` which only
+    /// touches the front layer
+    pub fn remove_all<Q: ?Sized + Hash + Eq>(&mut self, k: &Q)
+    where
+        K: Borrow<Q>,
+    {
+        for layer in &mut self.maps {
+            if layer.contains_key(k) {
+                Rc::make_mut(layer).remove(k);
+            }
+        }
+    }
+
+    /// Empties only the front (writable) layer, leaving deeper layers intact
+    pub fn clear(&mut self) {
+        if !self.maps.is_empty() && !self.maps[0].is_empty() {
+            Rc::make_mut(&mut self.maps[0]).clear();
+        }
+    }
+
+    /// Pops the front (innermost) scope off the chain and returns its map,
+    /// or None if the chain has no layers
+    pub fn pop_scope(&mut self) -> Option<ChainMapType<K, V>> {
+        if self.maps.is_empty() {
+            None
+        } else {
+            let layer = self.maps.remove(0);
+            Some(Rc::try_unwrap(layer).unwrap_or_else(|shared| (*shared).clone()))
+        }
+    }
+
+    /// Inserts `k`/`v` into the layer at `idx`, returning the previous value
+    /// at that key in that layer if any, or `Error::IndexOutOfRange` if `idx`
+    /// is past the end of the chain
+    pub fn insert_at(&mut self, idx: usize, k: K, v: V) -> Result<Option<V>, Error> {
+        if idx >= self.maps.len() {
+            Err(Error::IndexOutOfRange(idx))
+        } else {
+            Ok(Rc::make_mut(&mut self.maps[idx]).insert(k, v))
+        }
+    }
+
+    /// Returns a single consolidated map, front layer wins on overlapping keys
+    pub fn to_map(&self) -> ChainMapType<K, V> {
+        let mut combined_map = ChainMapType::new();
+
+        for m in &self.maps {
+            for (k, v) in m.iter() {
+                combined_map.entry(k.clone()).or_insert_with(|| v.clone());
+            }
+        }
+
+        combined_map
+    }
+
+    /// Resolves `k` across the chain: an `Entry::Occupied` on the first
+    /// (shadowing) layer that has it, otherwise an `Entry::Vacant` on the
+    /// front layer, creating one first if the chain is empty
+    pub fn entry(&mut self, k: K) -> Entry<'_, K, V> {
+        let idx = self.maps.iter().position(|m| m.contains_key(&k));
+
+        match idx {
+            Some(i) => match Rc::make_mut(&mut self.maps[i]).entry(k) {
+                hash_map::Entry::Occupied(inner) => Entry::Occupied(OccupiedEntry { inner }),
+                hash_map::Entry::Vacant(_) => unreachable!("key was just found in this layer"),
+            },
+            None => {
+                if self.maps.is_empty() {
+                    self.maps.push(Rc::new(ChainMapType::new()));
+                }
+                match Rc::make_mut(&mut self.maps[0]).entry(k) {
+                    hash_map::Entry::Vacant(inner) => Entry::Vacant(VacantEntry { inner }),
+                    hash_map::Entry::Occupied(_) => unreachable!("key was just found absent"),
+                }
+            }
+        }
+    }
+}
+
+/// An iterator over a `ChainMap`'s visible `(key, value)` pairs, produced by
+/// [`ChainMap::iter`].
+pub struct Iter<'a, K, V> {
+    layers: std::slice::Iter<'a, Layer<K, V>>,
+    current: Option<hash_map::Iter<'a, K, V>>,
+    seen: HashSet<&'a K>,
+}
+
+impl<'a, K: Hash + Eq, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(cur) = self.current.as_mut() {
+                for (k, v) in cur.by_ref() {
+                    if self.seen.insert(k) {
+                        return Some((k, v));
+                    }
+                }
+            }
+            self.current = Some(self.layers.next()?.iter());
+        }
+    }
+}
+
+/// An iterator over a `ChainMap`'s visible keys, produced by
+/// [`ChainMap::keys`].
+pub struct Keys<'a, K, V> {
+    inner: Iter<'a, K, V>,
+}
+
+impl<'a, K: Hash + Eq, V> Iterator for Keys<'a, K, V> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(k, _)| k)
+    }
+}
+
+/// An iterator over a `ChainMap`'s visible values, produced by
+/// [`ChainMap::values`].
+pub struct Values<'a, K, V> {
+    inner: Iter<'a, K, V>,
+}
+
+impl<'a, K: Hash + Eq, V> Iterator for Values<'a, K, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(_, v)| v)
+    }
+}
+
+impl<'a, K: Hash + Eq, V> IntoIterator for &'a ChainMap<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// A view into a single key's slot in a `ChainMap`, as returned by
+/// [`ChainMap::entry`].
+pub enum Entry<'a, K, V> {
+    Occupied(OccupiedEntry<'a, K, V>),
+    Vacant(VacantEntry<'a, K, V>),
+}
+
+impl<'a, K, V> Entry<'a, K, V> {
+    /// Ensures a value is present, inserting `default` into the front layer
+    /// if the entry is vacant, and returns a mutable reference to the value.
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default),
+        }
+    }
+
+    /// Like `or_insert`, but computes the default lazily if the entry is
+    /// vacant.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    /// Applies `f` to the value if the entry is occupied, leaving it
+    /// untouched otherwise.
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Self {
+        match self {
+            Entry::Occupied(mut entry) => {
+                f(entry.get_mut());
+                Entry::Occupied(entry)
+            }
+            Entry::Vacant(entry) => Entry::Vacant(entry),
+        }
+    }
+}
+
+/// An occupied entry, referencing the key's slot in whichever layer it was
+/// found in.
+pub struct OccupiedEntry<'a, K, V> {
+    inner: hash_map::OccupiedEntry<'a, K, V>,
+}
+
+impl<'a, K, V> OccupiedEntry<'a, K, V> {
+    pub fn get(&self) -> &V {
+        self.inner.get()
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        self.inner.get_mut()
+    }
+
+    pub fn into_mut(self) -> &'a mut V {
+        self.inner.into_mut()
+    }
+}
+
+/// A vacant entry; inserting into it writes to the front (writable) layer.
+pub struct VacantEntry<'a, K, V> {
+    inner: hash_map::VacantEntry<'a, K, V>,
+}
+
+impl<'a, K, V> VacantEntry<'a, K, V> {
+    pub fn insert(self, value: V) -> &'a mut V {
+        self.inner.insert(value)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -118,6 +419,20 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_chainmap_insert_creates_layer_on_empty_chain() {
+        let mut cmap: ChainMap<i32, String> = ChainMap::empty();
+        assert_eq!(cmap.insert(1, "one".to_string()), None);
+        assert_eq!(cmap.get(&1), Some(&"one".to_string()));
+    }
+
+    #[test]
+    fn test_chainmap_entry_creates_layer_on_empty_chain() {
+        let mut cmap: ChainMap<&str, i32> = ChainMap::empty();
+        cmap.entry("x").or_insert(1);
+        assert_eq!(cmap.get("x"), Some(&1));
+    }
+
     #[test]
     fn test_chainmap_get() {
         let mut m = HashMap::<i32, String>::new();
@@ -134,6 +449,216 @@ mod tests {
         assert_eq!(cmap.get(&5), None);
     }
 
+    #[test]
+    fn test_chainmap_clone_is_copy_on_write() {
+        let mut m = HashMap::<i32, String>::new();
+        m.insert(1, "one".to_string());
+
+        let cmap = ChainMap::new(vec![m]);
+        let mut cloned = cmap.clone();
+
+        cloned.insert(1, "uno".to_string());
+
+        assert_eq!(cmap.get(&1), Some(&"one".to_string()));
+        assert_eq!(cloned.get(&1), Some(&"uno".to_string()));
+    }
+
+    #[test]
+    fn test_chainmap_remove_unshadows_deeper_binding() {
+        let mut outer = HashMap::<&str, i32>::new();
+        outer.insert("x", 1);
+
+        let mut cmap = ChainMap::new(vec![outer]);
+        cmap.push_scope();
+        cmap.insert("x", 2);
+
+        assert_eq!(cmap.get("x"), Some(&2));
+
+        assert_eq!(cmap.remove("x"), Some(2));
+        assert_eq!(cmap.get("x"), Some(&1));
+        assert_eq!(cmap.remove("x"), None);
+    }
+
+    #[test]
+    fn test_chainmap_remove_all() {
+        let mut outer = HashMap::<&str, i32>::new();
+        outer.insert("x", 1);
+
+        let mut cmap = ChainMap::new(vec![outer]);
+        cmap.push_scope();
+        cmap.insert("x", 2);
+
+        cmap.remove_all("x");
+
+        assert_eq!(cmap.get("x"), None);
+    }
+
+    #[test]
+    fn test_chainmap_clear() {
+        let mut outer = HashMap::<&str, i32>::new();
+        outer.insert("x", 1);
+
+        let mut cmap = ChainMap::new(vec![outer]);
+        cmap.push_scope();
+        cmap.insert("y", 2);
+
+        cmap.clear();
+
+        assert_eq!(cmap.get("y"), None);
+        assert_eq!(cmap.get("x"), Some(&1));
+    }
+
+    #[test]
+    fn test_chainmap_iter_shadows_and_len() {
+        let mut outer = HashMap::<&str, i32>::new();
+        outer.insert("x", 1);
+        outer.insert("y", 2);
+
+        let mut inner = HashMap::<&str, i32>::new();
+        inner.insert("x", 100);
+        inner.insert("z", 3);
+
+        let cmap = ChainMap::new(vec![inner, outer]);
+
+        assert_eq!(cmap.len(), 3);
+
+        let mut seen: Vec<(&str, i32)> = cmap.iter().map(|(k, v)| (*k, *v)).collect();
+        seen.sort();
+
+        assert_eq!(seen, vec![("x", 100), ("y", 2), ("z", 3)]);
+
+        let mut keys: Vec<&str> = cmap.keys().copied().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["x", "y", "z"]);
+
+        let mut values: Vec<i32> = cmap.values().copied().collect();
+        values.sort();
+        assert_eq!(values, vec![2, 3, 100]);
+    }
+
+    #[test]
+    fn test_chainmap_for_loop() {
+        let mut m = HashMap::<&str, i32>::new();
+        m.insert("x", 1);
+
+        let cmap = ChainMap::new(vec![m]);
+
+        let mut total = 0;
+        for (_, v) in &cmap {
+            total += v;
+        }
+        assert_eq!(total, 1);
+    }
+
+    #[test]
+    fn test_chainmap_entry_or_insert_with() {
+        let mut cmap: ChainMap<&str, Vec<i32>> = ChainMap::new(vec![HashMap::new()]);
+
+        cmap.entry("a").or_insert_with(Vec::new).push(1);
+        cmap.entry("a").or_insert_with(Vec::new).push(2);
+
+        assert_eq!(cmap.get("a"), Some(&vec![1, 2]));
+    }
+
+    #[test]
+    fn test_chainmap_entry_and_modify() {
+        let mut outer = HashMap::<&str, i32>::new();
+        outer.insert("x", 1);
+
+        let mut cmap = ChainMap::new(vec![outer]);
+        cmap.push_scope();
+
+        cmap.entry("x").and_modify(|v| *v += 10).or_insert(0);
+
+        assert_eq!(cmap.get("x"), Some(&11));
+
+        cmap.entry("y").and_modify(|v| *v += 10).or_insert(5);
+        assert_eq!(cmap.get("y"), Some(&5));
+    }
+
+    #[test]
+    fn test_chainmap_new_child() {
+        let mut globals = HashMap::<&str, i32>::new();
+        globals.insert("x", 1);
+
+        let outer = ChainMap::new(vec![globals]);
+
+        let mut locals = HashMap::<&str, i32>::new();
+        locals.insert("y", 2);
+
+        let inner = outer.new_child(locals);
+
+        assert_eq!(inner.get("x"), Some(&1));
+        assert_eq!(inner.get("y"), Some(&2));
+        assert_eq!(outer.get("y"), None);
+    }
+
+    #[test]
+    fn test_chainmap_push_pop_scope() {
+        let mut globals = HashMap::<&str, i32>::new();
+        globals.insert("x", 1);
+
+        let mut cmap = ChainMap::new(vec![globals]);
+
+        cmap.push_scope();
+        cmap.insert("x", 2);
+        assert_eq!(cmap.get("x"), Some(&2));
+
+        let popped = cmap.pop_scope().unwrap();
+        assert_eq!(popped.get("x"), Some(&2));
+        assert_eq!(cmap.get("x"), Some(&1));
+
+        let mut empty: ChainMap<&str, i32> = ChainMap::empty();
+        assert_eq!(empty.pop_scope(), None);
+    }
+
+    #[test]
+    fn test_chainmap_insert_at() {
+        let mut m1 = HashMap::<i32, String>::new();
+        m1.insert(1, "one".to_string());
+
+        let mut m2 = HashMap::<i32, String>::new();
+        m2.insert(2, "two".to_string());
+
+        let mut cmap = ChainMap::new(vec![m1, m2]);
+
+        assert_eq!(cmap.insert_at(1, 2, "TWO".to_string()), Ok(Some("two".to_string())));
+        assert_eq!(cmap.get(&2), Some(&"TWO".to_string()));
+
+        assert_eq!(
+            cmap.insert_at(5, 9, "nine".to_string()),
+            Err(Error::IndexOutOfRange(5))
+        );
+    }
+
+    #[test]
+    fn test_chainmap_contains_key() {
+        let mut m = HashMap::<i32, String>::new();
+        m.insert(1, "one".to_string());
+        m.insert(2, "two".to_string());
+
+        let cmap = ChainMap::new(vec![m]);
+
+        assert!(cmap.contains_key(&1));
+        assert!(cmap.contains_key(&2));
+        assert!(!cmap.contains_key(&5));
+    }
+
+    #[test]
+    fn test_chainmap_borrowed_lookup() {
+        let mut m = HashMap::<String, i32>::new();
+        m.insert("Blocks".to_string(), 30);
+        m.insert("Monopoly".to_string(), 20);
+
+        let cmap = ChainMap::new(vec![m]);
+
+        // `&str` keys can be used to look up a `ChainMap<String, _>` without
+        // allocating an owned `String` first.
+        assert_eq!(cmap.get("Monopoly"), Some(&20));
+        assert!(cmap.contains_key("Blocks"));
+        assert!(!cmap.contains_key("Mario Bros."));
+    }
+
     #[test]
     fn test_chainmap_parents() {
         let mut m1 = HashMap::<i32, String>::new();
@@ -244,4 +769,20 @@ mod tests {
         assert_eq!(combined_map.get(&22), Some(&"twenty two".to_string()));
         assert_eq!(combined_map.get(&33), Some(&"thirty three".to_string()));
     }
+
+    #[test]
+    fn test_chainmap_to_map_shadowing_matches_get() {
+        let mut inner = HashMap::<&str, i32>::new();
+        inner.insert("x", 100);
+
+        let mut outer = HashMap::<&str, i32>::new();
+        outer.insert("x", 1);
+        outer.insert("y", 2);
+
+        let cmap = ChainMap::new(vec![inner, outer]);
+
+        assert_eq!(cmap.get("x"), Some(&100));
+        assert_eq!(cmap.to_map().get("x"), Some(&100));
+        assert_eq!(cmap.to_map().get("y"), Some(&2));
+    }
 }